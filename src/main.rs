@@ -1,30 +1,106 @@
+use bevy::gltf::GltfExtras;
 use bevy::prelude::{shape::CapsuleUvProfile, *};
+use bevy_ggrs::{ggrs, GgrsApp, GgrsPlugin, GgrsSchedule, PlayerInputs, ReadInputs};
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
 use bevy_mod_outline::*;
 use bevy_rapier3d::prelude::*;
 use leafwing_input_manager::{prelude::*, *};
+use noise::{NoiseFn, Perlin};
 use spooky_camera::{prelude::*, CameraFocus, PrimaryCamera};
 
 fn main() {
     App::new()
         .add_plugins(DefaultPlugins)
-        .add_plugins(RapierPhysicsPlugin::<NoUserData>::default())
+        .add_plugins(GgrsPlugin::<GgrsConfig>::default())
+        .add_plugins(RapierPhysicsPlugin::<NoUserData>::default().in_schedule(GgrsSchedule))
+        .insert_resource(RapierConfiguration {
+            timestep_mode: TimestepMode::Fixed {
+                dt: 1.0 / 60.0,
+                substeps: 1,
+            },
+            ..default()
+        })
         .add_plugins(RapierDebugRenderPlugin::default())
         .add_plugins(InputManagerPlugin::<PlayerAction>::default())
         .add_plugins(OutlinePlugin)
         .add_plugins(SpookyCameraPlugin)
         .add_plugins(WorldInspectorPlugin::default())
         .insert_resource(CameraTransform::default())
+        .insert_resource(GlobalStep::default())
+        .insert_resource(LevelConfig::default())
+        .insert_resource(LevelSource::default())
+        .register_type::<ColliderProxy>()
+        .register_type::<RigidBodyProxy>()
         .add_event::<NewWidgetEvent>()
-        .add_systems(Startup, setup)
-        .add_systems(Update, (tilt_controls, rotate_camera, set_camera_target))
+        .add_systems(
+            Startup,
+            (
+                setup,
+                generate_level.run_if(resource_equals(LevelSource::Procedural)),
+                load_level.run_if(resource_equals(LevelSource::Authored)),
+                start_rollback_session,
+            ),
+        )
+        .add_systems(
+            Update,
+            (parse_gltf_proxies, physics_replace_proxies).chain(),
+        )
+        .add_systems(
+            Update,
+            (
+                tilt_controls,
+                character_controller,
+                rotate_camera,
+                set_camera_target,
+            )
+                .run_if(not(resource_exists::<RollbackSessionConfig>())),
+        )
+        .add_systems(
+            Update,
+            (detect_widget_sensors, update_current_widget)
+                .chain()
+                .run_if(not(resource_exists::<RollbackSessionConfig>())),
+        )
+        .add_systems(PostUpdate, tilt_relative_camera_follow)
+        .add_systems(
+            Update,
+            track_previous_velocity
+                .before(character_controller)
+                .before(rollback_character_controller)
+                .before(PhysicsSet::SyncBackend),
+        )
         .add_systems(
             Update,
-            (detect_widget_sensors, update_current_widget).chain(),
+            (detect_tunneling, recover_from_tunneling)
+                .chain()
+                .after(PhysicsSet::Writeback),
+        )
+        .add_systems(ReadInputs, read_local_input)
+        .add_systems(
+            GgrsSchedule,
+            (
+                rollback_tilt_controls,
+                rollback_character_controller,
+                detect_widget_sensors,
+                update_current_widget,
+            )
+                .chain()
+                .before(PhysicsSet::SyncBackend),
         )
+        .rollback_component_with_clone::<Transform>()
+        .rollback_component_with_clone::<Velocity>()
+        .rollback_resource_with_clone::<CurrentWidget>()
         .run();
 }
 
+const GRAVITY: f32 = 9.81;
+const JUMP_HEIGHT: f32 = 2.0;
+const SPIN_SPEED: f32 = 10.0;
+const SPIN_DURATION: f32 = 0.4;
+const TUNNELING_RECOVERY_FRAMES: usize = 15;
+const RECOVERY_MARGIN: f32 = 0.05;
+const TUNNELING_EMBED_MARGIN: f32 = 0.05;
+
 #[derive(Component)]
 pub struct Player {
     pub height: f32,
@@ -42,6 +118,43 @@ pub struct Widget;
 #[derive(Component)]
 pub struct WidgetSensor(pub Entity);
 
+#[derive(Component, Clone, Copy, Reflect, serde::Deserialize)]
+#[serde(tag = "shape", rename_all = "snake_case")]
+pub enum ColliderProxy {
+    Cuboid { hx: f32, hy: f32, hz: f32 },
+    Ball { radius: f32 },
+    Capsule { half_height: f32, radius: f32 },
+    TriMesh,
+}
+
+#[derive(Component, Clone, Copy, Reflect, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RigidBodyProxy {
+    Dynamic,
+    Fixed,
+    KinematicPositionBased,
+}
+
+impl From<RigidBodyProxy> for RigidBody {
+    fn from(proxy: RigidBodyProxy) -> Self {
+        match proxy {
+            RigidBodyProxy::Dynamic => RigidBody::Dynamic,
+            RigidBodyProxy::Fixed => RigidBody::Fixed,
+            RigidBodyProxy::KinematicPositionBased => RigidBody::KinematicPositionBased,
+        }
+    }
+}
+
+#[derive(Component, Clone, Copy)]
+pub struct LinkToWidget(pub Entity);
+
+#[derive(serde::Deserialize)]
+pub struct GltfPhysicsExtras {
+    pub collider: ColliderProxy,
+    #[serde(default)]
+    pub rigid_body: Option<RigidBodyProxy>,
+}
+
 #[derive(Event)]
 pub struct NewWidgetEvent {
     pub old_widget: Entity,
@@ -88,9 +201,118 @@ impl InputListenerBundle {
     }
 }
 
-#[derive(Resource, Default)]
+#[derive(Resource, Default, Clone)]
 pub struct CurrentWidget(pub Option<Entity>);
 
+#[derive(Component, Default)]
+pub struct Grounded(pub bool);
+
+#[derive(Resource)]
+pub struct GlobalStep(pub f32);
+
+impl Default for GlobalStep {
+    fn default() -> Self {
+        GlobalStep(0.3)
+    }
+}
+
+#[derive(Component)]
+pub struct SpinDecay(pub Timer);
+
+#[derive(Resource, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LevelSource {
+    #[default]
+    Procedural,
+    Authored,
+}
+
+#[derive(Resource, Clone)]
+pub struct LevelConfig {
+    pub grid_size: UVec2,
+    pub cell_size: f32,
+    pub noise_frequency: f64,
+    pub noise_amplitude: f32,
+    pub seed: u32,
+}
+
+impl Default for LevelConfig {
+    fn default() -> Self {
+        LevelConfig {
+            grid_size: UVec2::new(5, 5),
+            cell_size: 12.0,
+            noise_frequency: 0.15,
+            noise_amplitude: 4.0,
+            seed: 0,
+        }
+    }
+}
+
+#[derive(Component, Default, Clone, Copy)]
+pub struct PreviousVelocity(pub Velocity);
+
+#[derive(Component)]
+pub struct Tunneling {
+    pub frames: usize,
+    pub dir: Vec3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RollbackInput {
+    pub tilt: Vec2,
+    pub camera_pan: Vec2,
+    pub buttons: u8,
+}
+
+impl RollbackInput {
+    const JUMP: u8 = 1 << 0;
+    const SPIN: u8 = 1 << 1;
+
+    pub fn jump(&self) -> bool {
+        self.buttons & Self::JUMP != 0
+    }
+
+    pub fn spin(&self) -> bool {
+        self.buttons & Self::SPIN != 0
+    }
+
+    pub fn to_bytes(self) -> [u8; 17] {
+        let mut bytes = [0u8; 17];
+        bytes[0..4].copy_from_slice(&self.tilt.x.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.tilt.y.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.camera_pan.x.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.camera_pan.y.to_le_bytes());
+        bytes[16] = self.buttons;
+        bytes
+    }
+
+    pub fn from_bytes(bytes: [u8; 17]) -> Self {
+        RollbackInput {
+            tilt: Vec2::new(
+                f32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+                f32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            ),
+            camera_pan: Vec2::new(
+                f32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+                f32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+            ),
+            buttons: bytes[16],
+        }
+    }
+}
+
+pub struct GgrsConfig;
+
+impl ggrs::Config for GgrsConfig {
+    type Input = RollbackInput;
+    type State = u8;
+    type Address = String;
+}
+
+#[derive(Resource, Clone, Copy)]
+pub struct RollbackSessionConfig {
+    pub local_handle: usize,
+}
+
 #[derive(Bundle)]
 pub struct PlayerBundle {
     pub player: Player,
@@ -102,10 +324,12 @@ pub struct PlayerBundle {
     pub computed_visibility: ComputedVisibility,
     pub rigid_body: RigidBody,
     pub velocity: Velocity,
+    pub previous_velocity: PreviousVelocity,
     pub collider: Collider,
     pub friction: Friction,
     pub gravity_scale: GravityScale,
     pub mass_properties: ColliderMassProperties,
+    pub ccd: Ccd,
 }
 
 impl PlayerBundle {
@@ -128,6 +352,8 @@ impl Default for PlayerBundle {
             },
             gravity_scale: GravityScale(5.0),
             velocity: Velocity::default(),
+            previous_velocity: PreviousVelocity::default(),
+            ccd: Ccd::enabled(),
             mesh: Handle::default(),
             transform: Transform::default(),
             material: Handle::default(),
@@ -165,71 +391,183 @@ fn setup(
             .with_height(height),
         )
         .insert(InputListenerBundle::input_map());
+}
 
-    let blue_mat = materials.add(Color::BLUE.into());
+fn height_band(noise_value: f32) -> f32 {
+    (noise_value + 1.0) * 0.5
+}
 
-    let start_id = commands
-        .spawn((
-            PbrBundle {
-                mesh: meshes.add(Mesh::from(shape::Box::new(10.0, 0.5, 10.0))),
-                material: blue_mat.clone(),
-                transform: Transform::from_translation(Vec3::Y * -1.0),
-                ..default()
-            },
-            RigidBody::KinematicPositionBased,
-            Collider::cuboid(5.0, 0.25, 5.0),
-            Friction {
-                coefficient: 0.2,
-                combine_rule: CoefficientCombineRule::Min,
-            },
-            Widget,
-            OutlineBundle {
-                outline: OutlineVolume {
-                    visible: true,
-                    colour: Color::rgba(0.0, 1.0, 0.0, 1.0),
-                    width: 15.0,
-                },
-                ..default()
-            },
-        ))
-        .with_children(|parent| {
-            parent.spawn((
-                TransformBundle::default(),
-                Collider::ball(5.1),
-                RigidBody::KinematicPositionBased,
-                Sensor,
-                WidgetSensor(parent.parent_entity()),
-            ));
-        })
-        .id();
+fn generate_level(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    level_config: Res<LevelConfig>,
+) {
+    let noise = Perlin::new(level_config.seed);
+    let mut start_id = None;
 
-    commands.insert_resource(CurrentWidget(Some(start_id)));
+    for x in 0..level_config.grid_size.x {
+        for z in 0..level_config.grid_size.y {
+            let sample = [
+                x as f64 * level_config.noise_frequency,
+                z as f64 * level_config.noise_frequency,
+            ];
+            let noise_value = noise.get(sample) as f32;
+            let height = -1.0 + noise_value * level_config.noise_amplitude;
+            let height_band = height_band(noise_value);
 
-    commands
-        .spawn((
-            PbrBundle {
-                mesh: meshes.add(Mesh::from(shape::Box::new(10.0, 0.5, 10.0))),
-                material: blue_mat.clone(),
-                transform: Transform::from_translation(Vec3::new(0.0, -1.0, 12.0)),
-                ..default()
-            },
-            RigidBody::KinematicPositionBased,
-            Collider::cuboid(5.0, 0.25, 5.0),
-            Friction {
-                coefficient: 0.2,
-                combine_rule: CoefficientCombineRule::Min,
-            },
-            Widget,
-        ))
-        .with_children(|parent| {
+            let translation = Vec3::new(
+                x as f32 * level_config.cell_size,
+                height,
+                z as f32 * level_config.cell_size,
+            );
+
+            let widget_id = commands
+                .spawn((
+                    PbrBundle {
+                        mesh: meshes.add(Mesh::from(shape::Box::new(10.0, 0.5, 10.0))),
+                        material: materials
+                            .add(Color::rgb(height_band, 0.3, 1.0 - height_band).into()),
+                        transform: Transform::from_translation(translation),
+                        ..default()
+                    },
+                    RigidBody::KinematicPositionBased,
+                    Collider::cuboid(5.0, 0.25, 5.0),
+                    Friction {
+                        coefficient: 0.2,
+                        combine_rule: CoefficientCombineRule::Min,
+                    },
+                    Widget,
+                ))
+                .with_children(|parent| {
+                    parent.spawn((
+                        TransformBundle::default(),
+                        Collider::ball(5.1),
+                        RigidBody::KinematicPositionBased,
+                        Sensor,
+                        WidgetSensor(parent.parent_entity()),
+                    ));
+                })
+                .id();
+
+            if start_id.is_none() {
+                commands.entity(widget_id).insert(OutlineBundle {
+                    outline: OutlineVolume {
+                        visible: true,
+                        colour: Color::rgba(0.0, 1.0, 0.0, 1.0),
+                        width: 15.0,
+                    },
+                    ..default()
+                });
+                start_id = Some(widget_id);
+            }
+        }
+    }
+
+    commands.insert_resource(CurrentWidget(start_id));
+}
+
+fn load_level(asset_server: Res<AssetServer>, mut commands: Commands) {
+    commands.spawn(SceneBundle {
+        scene: asset_server.load("levels/widget_field.glb#Scene0"),
+        ..default()
+    });
+}
+
+fn parse_gltf_proxies(
+    mut commands: Commands,
+    extras_query: Query<(Entity, &GltfExtras, Option<&Parent>), Added<GltfExtras>>,
+    widget_query: Query<(), With<Widget>>,
+    parent_query: Query<&Parent>,
+) {
+    for (entity, extras, parent) in &extras_query {
+        let Ok(parsed) = serde_json::from_str::<GltfPhysicsExtras>(&extras.value) else {
+            continue;
+        };
+
+        commands.entity(entity).insert(parsed.collider);
+        if let Some(rigid_body_proxy) = parsed.rigid_body {
+            commands.entity(entity).insert(rigid_body_proxy);
+        }
+
+        let mut ancestor = parent.map(Parent::get);
+        while let Some(candidate) = ancestor {
+            if widget_query.get(candidate).is_ok() {
+                commands.entity(entity).insert(LinkToWidget(candidate));
+                break;
+            }
+            ancestor = parent_query.get(candidate).ok().map(Parent::get);
+        }
+    }
+}
+
+fn physics_replace_proxies(
+    mut commands: Commands,
+    meshes: Res<Assets<Mesh>>,
+    proxy_query: Query<
+        (
+            Entity,
+            &ColliderProxy,
+            Option<&RigidBodyProxy>,
+            Option<&Handle<Mesh>>,
+            Option<&LinkToWidget>,
+        ),
+        Added<ColliderProxy>,
+    >,
+    widget_query: Query<Entity, With<Widget>>,
+) {
+    for (entity, collider_proxy, rigid_body_proxy, mesh_handle, link_to_widget) in &proxy_query {
+        let collider = match collider_proxy {
+            ColliderProxy::Cuboid { hx, hy, hz } => Collider::cuboid(*hx, *hy, *hz),
+            ColliderProxy::Ball { radius } => Collider::ball(*radius),
+            ColliderProxy::Capsule {
+                half_height,
+                radius,
+            } => Collider::capsule_y(*half_height, *radius),
+            ColliderProxy::TriMesh => {
+                let Some(mesh) = mesh_handle.and_then(|handle| meshes.get(handle)) else {
+                    continue;
+                };
+                let Some(collider) =
+                    Collider::from_bevy_mesh(mesh, &ComputedColliderShape::TriMesh)
+                else {
+                    continue;
+                };
+                collider
+            }
+        };
+
+        commands
+            .entity(entity)
+            .insert(collider)
+            .insert(
+                rigid_body_proxy
+                    .copied()
+                    .map(RigidBody::from)
+                    .unwrap_or(RigidBody::Fixed),
+            )
+            .remove::<ColliderProxy>()
+            .remove::<RigidBodyProxy>();
+
+        let Some(LinkToWidget(widget_entity)) = link_to_widget.copied() else {
+            continue;
+        };
+        let Ok(widget_entity) = widget_query.get(widget_entity) else {
+            continue;
+        };
+
+        commands.entity(widget_entity).add_child(entity);
+        commands.entity(entity).with_children(|parent| {
             parent.spawn((
                 TransformBundle::default(),
                 Collider::ball(5.1),
                 RigidBody::KinematicPositionBased,
                 Sensor,
-                WidgetSensor(parent.parent_entity()),
+                WidgetSensor(widget_entity),
             ));
         });
+        commands.entity(entity).remove::<LinkToWidget>();
+    }
 }
 
 fn shrink(
@@ -282,6 +620,307 @@ fn tilt_controls(
     }
 }
 
+pub fn start_rollback_session(mut commands: Commands) {
+    let mut session_builder = ggrs::SessionBuilder::<GgrsConfig>::new()
+        .with_num_players(1)
+        .add_player(ggrs::PlayerType::Local, 0)
+        .expect("adding local player 0 to the synctest session");
+
+    let session = session_builder
+        .start_synctest_session()
+        .expect("starting synctest session");
+
+    commands.insert_resource(RollbackSessionConfig { local_handle: 0 });
+    commands.insert_resource(bevy_ggrs::Session::SyncTestSession(session));
+}
+
+fn read_local_input(
+    player_query: Query<&ActionState<PlayerAction>, Without<Widget>>,
+) -> RollbackInput {
+    let Ok(action) = player_query.get_single() else {
+        return RollbackInput::default();
+    };
+
+    let mut buttons = 0u8;
+    if action.just_pressed(PlayerAction::Jump) {
+        buttons |= RollbackInput::JUMP;
+    }
+    if action.just_pressed(PlayerAction::Spin) {
+        buttons |= RollbackInput::SPIN;
+    }
+
+    RollbackInput {
+        tilt: action
+            .clamped_axis_pair(PlayerAction::Tilt)
+            .map(|pair| pair.xy())
+            .unwrap_or_default(),
+        camera_pan: action
+            .axis_pair(PlayerAction::CameraPan)
+            .map(|pair| pair.xy())
+            .unwrap_or_default(),
+        buttons,
+    }
+}
+
+fn rollback_tilt_controls(
+    camera_focus: Res<CameraFocus>,
+    current_widget: Res<CurrentWidget>,
+    session_config: Res<RollbackSessionConfig>,
+    inputs: Res<PlayerInputs<GgrsConfig>>,
+    mut level_query: Query<(Entity, &mut Transform), With<Widget>>,
+) {
+    let Some(widget) = current_widget.0 else {
+        return;
+    };
+
+    let (input, _) = inputs[session_config.local_handle];
+    if input.tilt == Vec2::ZERO {
+        return;
+    }
+
+    let max_rot: f32 = 7.0;
+    let forward = camera_focus.forward_flat();
+    let right = camera_focus.right_flat();
+    let new_rotation = Quat::from_axis_angle(forward, (max_rot * input.tilt.x).to_radians())
+        * Quat::from_axis_angle(right, (max_rot * -input.tilt.y).to_radians());
+
+    for (entity, mut transform) in &mut level_query {
+        if entity == widget {
+            transform.rotation = new_rotation;
+        }
+    }
+}
+
+fn step_up_over_ledge(
+    rapier_context: &RapierContext,
+    filter: QueryFilter,
+    step_height: f32,
+    player_height: f32,
+    translation: &mut Vec3,
+    horizontal_velocity: Vec3,
+) {
+    let horizontal_velocity = Vec3::new(horizontal_velocity.x, 0.0, horizontal_velocity.z);
+    if step_height <= 0.0 || horizontal_velocity.length_squared() <= f32::EPSILON {
+        return;
+    }
+
+    let half_height = player_height * 0.5;
+    let foot = *translation - Vec3::Y * half_height;
+    let forward = horizontal_velocity.normalize();
+    let probe_origin = foot + forward * 0.6 + Vec3::Y * step_height;
+    if let Some((_, toi)) =
+        rapier_context.cast_ray(probe_origin, Vec3::NEG_Y, step_height, true, filter)
+    {
+        translation.y = probe_origin.y - toi + half_height;
+    }
+}
+
+fn rollback_character_controller(
+    mut commands: Commands,
+    global_step: Res<GlobalStep>,
+    session_config: Res<RollbackSessionConfig>,
+    inputs: Res<PlayerInputs<GgrsConfig>>,
+    rapier_context: Res<RapierContext>,
+    mut player_query: Query<(
+        Entity,
+        &Player,
+        &mut Transform,
+        &mut Velocity,
+        &GravityScale,
+    )>,
+) {
+    if let Ok((entity, player, mut transform, mut velocity, gravity_scale)) =
+        player_query.get_single_mut()
+    {
+        let (input, _) = inputs[session_config.local_handle];
+
+        let ground_distance = player.height * 0.5 + 0.05;
+        let filter = QueryFilter::default().exclude_rigid_body(entity);
+        let grounded = rapier_context
+            .cast_ray(
+                transform.translation,
+                Vec3::NEG_Y,
+                ground_distance,
+                true,
+                filter,
+            )
+            .is_some();
+
+        commands.entity(entity).insert(Grounded(grounded));
+
+        step_up_over_ledge(
+            &rapier_context,
+            filter,
+            global_step.0,
+            player.height,
+            &mut transform.translation,
+            velocity.linvel,
+        );
+
+        if grounded && input.jump() {
+            let jump_speed = (2.0 * GRAVITY * gravity_scale.0 * JUMP_HEIGHT).sqrt();
+            velocity.linvel.y = jump_speed;
+        }
+
+        if input.spin() {
+            velocity.angvel = Vec3::Y * SPIN_SPEED;
+            commands
+                .entity(entity)
+                .insert(SpinDecay(Timer::from_seconds(
+                    SPIN_DURATION,
+                    TimerMode::Once,
+                )));
+        }
+    }
+}
+
+fn character_controller(
+    mut commands: Commands,
+    time: Res<Time>,
+    global_step: Res<GlobalStep>,
+    rapier_context: Res<RapierContext>,
+    mut player_query: Query<(
+        Entity,
+        &Player,
+        &mut Transform,
+        &mut Velocity,
+        &GravityScale,
+        &ActionState<PlayerAction>,
+        Option<&mut SpinDecay>,
+    )>,
+) {
+    if let Ok((entity, player, mut transform, mut velocity, gravity_scale, action, spin_decay)) =
+        player_query.get_single_mut()
+    {
+        let ground_distance = player.height * 0.5 + 0.05;
+        let filter = QueryFilter::default().exclude_rigid_body(entity);
+
+        let grounded = rapier_context
+            .cast_ray(
+                transform.translation,
+                Vec3::NEG_Y,
+                ground_distance,
+                true,
+                filter,
+            )
+            .is_some();
+
+        commands.entity(entity).insert(Grounded(grounded));
+
+        step_up_over_ledge(
+            &rapier_context,
+            filter,
+            global_step.0,
+            player.height,
+            &mut transform.translation,
+            velocity.linvel,
+        );
+
+        if grounded && action.just_pressed(PlayerAction::Jump) {
+            let jump_speed = (2.0 * GRAVITY * gravity_scale.0 * JUMP_HEIGHT).sqrt();
+            velocity.linvel.y = jump_speed;
+        }
+
+        if action.just_pressed(PlayerAction::Spin) {
+            velocity.angvel = Vec3::Y * SPIN_SPEED;
+            commands
+                .entity(entity)
+                .insert(SpinDecay(Timer::from_seconds(
+                    SPIN_DURATION,
+                    TimerMode::Once,
+                )));
+        }
+
+        if let Some(mut spin_decay) = spin_decay {
+            spin_decay.0.tick(time.delta());
+            if spin_decay.0.finished() {
+                velocity.angvel = Vec3::ZERO;
+                commands.entity(entity).remove::<SpinDecay>();
+            }
+        }
+    }
+}
+
+fn track_previous_velocity(mut player_query: Query<(&Velocity, &mut PreviousVelocity)>) {
+    for (velocity, mut previous_velocity) in &mut player_query {
+        previous_velocity.0 = *velocity;
+    }
+}
+
+fn detect_tunneling(
+    mut commands: Commands,
+    time: Res<Time>,
+    rapier_context: Res<RapierContext>,
+    player_query: Query<
+        (Entity, &Transform, &PreviousVelocity),
+        (With<Player>, Without<Tunneling>),
+    >,
+) {
+    if let Ok((entity, transform, previous_velocity)) = player_query.get_single() {
+        let linvel = previous_velocity.0.linvel;
+        if linvel.length_squared() <= f32::EPSILON {
+            return;
+        }
+
+        let dir = linvel.normalize();
+        let swept_distance = linvel.length() * time.delta_seconds();
+        let previous_translation = transform.translation - linvel * time.delta_seconds();
+        let filter = QueryFilter::default().exclude_rigid_body(entity);
+
+        if let Some((_, gap_to_surface)) = rapier_context.cast_ray(
+            previous_translation,
+            dir,
+            swept_distance + TUNNELING_EMBED_MARGIN,
+            true,
+            filter,
+        ) {
+            let surface_point = previous_translation + dir * gap_to_surface;
+            let distance_past_surface = (transform.translation - surface_point).dot(dir);
+            if distance_past_surface > TUNNELING_EMBED_MARGIN {
+                commands.entity(entity).insert(Tunneling {
+                    frames: TUNNELING_RECOVERY_FRAMES,
+                    dir,
+                });
+            }
+        }
+    }
+}
+
+fn recover_from_tunneling(
+    mut commands: Commands,
+    rapier_context: Res<RapierContext>,
+    mut player_query: Query<(Entity, &mut Transform, &mut Velocity, &mut Tunneling)>,
+) {
+    if let Ok((entity, mut transform, mut velocity, mut tunneling)) = player_query.get_single_mut()
+    {
+        let filter = QueryFilter::default().exclude_rigid_body(entity);
+        if let Some((_, toi)) = rapier_context.cast_ray(
+            transform.translation,
+            -tunneling.dir,
+            f32::MAX,
+            true,
+            filter,
+        ) {
+            transform.translation -= tunneling.dir * (toi + RECOVERY_MARGIN);
+        }
+
+        if tunneling.dir.x.abs() > f32::EPSILON {
+            velocity.linvel.x = 0.0;
+        }
+        if tunneling.dir.y.abs() > f32::EPSILON {
+            velocity.linvel.y = 0.0;
+        }
+        if tunneling.dir.z.abs() > f32::EPSILON {
+            velocity.linvel.z = 0.0;
+        }
+
+        tunneling.frames = tunneling.frames.saturating_sub(1);
+        if tunneling.frames == 0 {
+            commands.entity(entity).remove::<Tunneling>();
+        }
+    }
+}
+
 fn rotate_camera(
     mut camera_query: Query<&mut PrimaryCamera>,
     player_query: Query<&ActionState<PlayerAction>>,
@@ -313,6 +952,56 @@ fn rotate_camera(
     }
 }
 
+const CAMERA_OCCLUSION_MARGIN: f32 = 0.3;
+
+fn tilt_relative_camera_follow(
+    current_widget: Res<CurrentWidget>,
+    rapier_context: Res<RapierContext>,
+    widget_query: Query<&Transform, With<Widget>>,
+    player_query: Query<&Transform, With<Player>>,
+    mut camera_query: Query<(Entity, &PrimaryCamera, &mut Transform), Without<Player>>,
+) {
+    let Ok((camera_entity, camera, mut camera_transform)) = camera_query.get_single_mut() else {
+        return;
+    };
+    if player_query.get_single().is_err() {
+        return;
+    }
+
+    let up = current_widget
+        .0
+        .and_then(|widget| widget_query.get(widget).ok())
+        .map(|widget_transform| widget_transform.rotation * Vec3::Y)
+        .unwrap_or(Vec3::Y);
+
+    let target = camera.target;
+    let horizontal_radius = Vec2::new(camera.offset.x, camera.offset.z).length();
+    let current_offset = camera_transform.translation - target;
+    let current_offset_on_plane = current_offset - up * current_offset.dot(up);
+    let horizontal_offset = if current_offset_on_plane.length_squared() > f32::EPSILON {
+        current_offset_on_plane.normalize() * horizontal_radius
+    } else {
+        Vec3::new(camera.offset.x, 0.0, camera.offset.z)
+    };
+    let mut desired_position = target + horizontal_offset + up * camera.offset.y;
+
+    let to_target = target - desired_position;
+    let distance = to_target.length();
+    if distance > f32::EPSILON {
+        let direction = to_target / distance;
+        let filter = QueryFilter::default().exclude_rigid_body(camera_entity);
+        if let Some((_, toi)) =
+            rapier_context.cast_ray(desired_position, direction, distance, true, filter)
+        {
+            let pulled_in_distance = (toi - CAMERA_OCCLUSION_MARGIN).max(0.0);
+            desired_position += direction * pulled_in_distance;
+        }
+    }
+
+    camera_transform.translation = desired_position;
+    camera_transform.look_at(target, up);
+}
+
 fn set_camera_target(
     time: Res<Time>,
     mut camera_query: Query<&mut PrimaryCamera>,
@@ -368,3 +1057,30 @@ fn update_current_widget(
         current_widget.0 = Some(event.new_widget);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollback_input_round_trips_through_bytes() {
+        let input = RollbackInput {
+            tilt: Vec2::new(-1.0, 0.5),
+            camera_pan: Vec2::new(0.25, -0.75),
+            buttons: RollbackInput::JUMP | RollbackInput::SPIN,
+        };
+
+        let round_tripped = RollbackInput::from_bytes(input.to_bytes());
+
+        assert_eq!(input, round_tripped);
+        assert!(round_tripped.jump());
+        assert!(round_tripped.spin());
+    }
+
+    #[test]
+    fn height_band_stays_within_unit_range() {
+        assert_eq!(height_band(-1.0), 0.0);
+        assert_eq!(height_band(0.0), 0.5);
+        assert_eq!(height_band(1.0), 1.0);
+    }
+}